@@ -0,0 +1,86 @@
+//! aarch64 relocation types, instruction-cache coherence and the aarch64
+//! `enter_kernel` trampoline (implemented in assembly, linked in separately).
+
+use core::arch::asm;
+use core::fmt::Write;
+use elfloader::{ElfLoaderErr, RelocationEntry, RelocationType};
+
+use crate::BootInfo;
+
+use super::Arch;
+
+// Minimum architecturally-guaranteed cache line size; `dc`/`ic` operate on whichever
+// line size the CPU actually implements, so walking it in steps this small is always safe.
+const CACHE_LINE_SIZE: u64 = 16;
+
+extern "C" {
+    #[allow(improper_ctypes)]
+    fn enter_kernel(entry: u64, info: *const BootInfo);
+}
+
+pub struct AArch64;
+
+impl Arch for AArch64 {
+    fn relocation_value(
+        base: u64,
+        entry: &RelocationEntry,
+        stdout: &mut dyn Write
+    ) -> Result<u64, ElfLoaderErr> {
+        use RelocationType::aarch64;
+        use elfloader::arch::aarch64::RelocationTypes::*;
+
+        match entry.rtype {
+            aarch64(R_AARCH64_RELATIVE) => {
+                let addend = entry.addend
+                    .ok_or(ElfLoaderErr::UnsupportedRelocationEntry)?;
+                let value = base + addend;
+
+                writeln!(stdout, "Relocation: AARCH64_RELATIVE: = {:#x}", value).unwrap();
+
+                Ok(value)
+            }
+            aarch64(R_AARCH64_ABS64) => {
+                let addend = entry.addend
+                    .ok_or(ElfLoaderErr::UnsupportedRelocationEntry)?;
+                let value = base + addend;
+
+                writeln!(stdout, "Relocation: AARCH64_ABS64: = {:#x}", value).unwrap();
+
+                Ok(value)
+            }
+            aarch64(R_AARCH64_GLOB_DAT) | aarch64(R_AARCH64_JUMP_SLOT) => {
+                writeln!(stdout, "Relocation: AARCH64_GLOB_DAT/JUMP_SLOT: = {:#x}", base).unwrap();
+
+                Ok(base)
+            }
+            _ => Err(ElfLoaderErr::UnsupportedRelocationEntry),
+        }
+    }
+
+    unsafe fn sync_instruction_cache(start: u64, end: u64) {
+        let start = start & !(CACHE_LINE_SIZE - 1);
+        let end = (end + CACHE_LINE_SIZE - 1) & !(CACHE_LINE_SIZE - 1);
+
+        // Clean each line to the point of unification so the instruction cache's
+        // subsequent fetch observes the bytes the loader just copied.
+        let mut address = start;
+        while address < end {
+            asm!("dc cvau, {0}", in(reg) address);
+            address += CACHE_LINE_SIZE;
+        }
+        asm!("dsb ish");
+
+        // Invalidate the now-stale instruction cache lines and flush the pipeline.
+        let mut address = start;
+        while address < end {
+            asm!("ic ivau, {0}", in(reg) address);
+            address += CACHE_LINE_SIZE;
+        }
+        asm!("isb");
+    }
+
+    unsafe fn enter_kernel(entry: u64, info: *const BootInfo) -> ! {
+        enter_kernel(entry, info);
+        loop {}
+    }
+}
@@ -0,0 +1,43 @@
+//! Architecture-specific parts of the loader: relocation decoding, cache
+//! maintenance and the final jump into the kernel. Everything else in the
+//! loader (ELF parsing, memory map handling, graphics, ...) is architecture
+//! agnostic and lives in `main.rs`.
+
+use core::fmt::Write;
+use elfloader::{ElfLoaderErr, RelocationEntry};
+
+use crate::BootInfo;
+
+#[cfg(target_arch = "x86_64")]
+pub mod x86_64;
+
+#[cfg(target_arch = "aarch64")]
+pub mod aarch64;
+
+#[cfg(target_arch = "x86_64")]
+pub use self::x86_64::X86_64 as CurrentArch;
+
+#[cfg(target_arch = "aarch64")]
+pub use self::aarch64::AArch64 as CurrentArch;
+
+pub trait Arch {
+    /// Resolve a dynamic relocation entry against the given load base, returning the
+    /// value to store at `base + entry.offset`.
+    ///
+    /// The kernel is self-contained, so every dynamic symbol resolves to an address
+    /// within the kernel image itself: the symbol's value is already folded into the
+    /// addend by the linker, leaving only the load base to add.
+    fn relocation_value(
+        base: u64,
+        entry: &RelocationEntry,
+        stdout: &mut dyn Write
+    ) -> Result<u64, ElfLoaderErr>;
+
+    /// Make `start..end` coherently visible to the instruction cache after it has been
+    /// freshly written by the loader. A no-op on architectures that keep the
+    /// instruction and data caches coherent in hardware.
+    unsafe fn sync_instruction_cache(start: u64, end: u64);
+
+    /// Jump into the kernel entry point. Never returns.
+    unsafe fn enter_kernel(entry: u64, info: *const BootInfo) -> !;
+}
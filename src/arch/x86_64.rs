@@ -0,0 +1,63 @@
+//! x86-64 relocation types, as used in `.rela.dyn`/`.rela.plt`, and the x86-64
+//! `enter_kernel` trampoline (implemented in assembly, linked in separately).
+
+use core::fmt::Write;
+use elfloader::{ElfLoaderErr, RelocationEntry, RelocationType};
+
+use crate::BootInfo;
+
+use super::Arch;
+
+extern "C" {
+    #[allow(improper_ctypes)]
+    fn enter_kernel(entry: u64, info: *const BootInfo);
+}
+
+pub struct X86_64;
+
+impl Arch for X86_64 {
+    fn relocation_value(
+        base: u64,
+        entry: &RelocationEntry,
+        stdout: &mut dyn Write
+    ) -> Result<u64, ElfLoaderErr> {
+        use RelocationType::x86_64;
+        use elfloader::arch::x86_64::RelocationTypes::*;
+
+        match entry.rtype {
+            x86_64(R_AMD64_RELATIVE) | x86_64(R_AMD64_RELATIVE64) => {
+                let addend = entry.addend
+                    .ok_or(ElfLoaderErr::UnsupportedRelocationEntry)?;
+                let value = base + addend;
+
+                writeln!(stdout, "Relocation: AMD64_RELATIVE: = {:#x}", value).unwrap();
+
+                Ok(value)
+            }
+            x86_64(R_AMD64_64) => {
+                let addend = entry.addend
+                    .ok_or(ElfLoaderErr::UnsupportedRelocationEntry)?;
+                let value = base + addend;
+
+                writeln!(stdout, "Relocation: AMD64_64: = {:#x}", value).unwrap();
+
+                Ok(value)
+            }
+            x86_64(R_AMD64_GLOB_DAT) | x86_64(R_AMD64_JUMP_SLOT) => {
+                writeln!(stdout, "Relocation: AMD64_GLOB_DAT/JUMP_SLOT: = {:#x}", base).unwrap();
+
+                Ok(base)
+            }
+            _ => Err(ElfLoaderErr::UnsupportedRelocationEntry),
+        }
+    }
+
+    unsafe fn sync_instruction_cache(_start: u64, _end: u64) {
+        // x86-64 keeps the instruction and data caches coherent in hardware.
+    }
+
+    unsafe fn enter_kernel(entry: u64, info: *const BootInfo) -> ! {
+        enter_kernel(entry, info);
+        loop {}
+    }
+}
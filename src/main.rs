@@ -7,8 +7,11 @@ extern crate alloc;
 use alloc::vec::Vec;
 use core::{alloc::Layout, fmt::Write, mem, panic::PanicInfo, ptr};
 use uefi::{
-    allocator, boot::{self, MemoryType}, fs::{FileSystem, FileSystemResult}, mem::memory_map::MemoryMap, prelude::entry, proto::{console::gop::GraphicsOutput, media::fs::SimpleFileSystem}, table::{
-        boot::{BootServices, ScopedProtocol},
+    allocator, boot::{self, MemoryType}, fs::{FileSystem, FileSystemResult}, mem::memory_map::MemoryMap, prelude::entry, proto::{
+        console::gop::{GraphicsOutput, Mode, PixelFormat as GopPixelFormat},
+        media::fs::SimpleFileSystem
+    }, table::{
+        boot::{AllocateType, BootServices, ScopedProtocol},
         cfg,
         Boot,
         SystemTable
@@ -16,9 +19,17 @@ use uefi::{
 };
 use elfloader::*;
 
-extern "C" {
-    #[allow(improper_ctypes)]
-    fn enter_kernel(entry: u64, info: *const BootInfo);
+mod arch;
+
+/// UEFI pages are always 4 KiB, regardless of the underlying architecture page size.
+const PAGE_SIZE: u64 = 0x1000;
+
+fn page_align_down(address: u64) -> u64 {
+    address & !(PAGE_SIZE - 1)
+}
+
+fn page_align_up(address: u64) -> u64 {
+    (address + PAGE_SIZE - 1) & !(PAGE_SIZE - 1)
 }
 
 pub enum RegionKind {
@@ -37,13 +48,39 @@ impl Region {
     pub fn new(kind: RegionKind, start: u64, end: u64) -> Region {
         Region { kind, start, end }
     }
+
+    pub fn empty() -> Region {
+        Region::new(RegionKind::Unknown, 0, 0)
+    }
+}
+
+pub struct Cmdline {
+    pub data: *const u8,
+    pub length: usize
+}
+
+impl Cmdline {
+    pub fn empty() -> Cmdline {
+        Cmdline { data: ptr::null(), length: 0 }
+    }
+
+    pub fn new(data: *const u8, length: usize) -> Cmdline {
+        Cmdline { data, length }
+    }
+}
+
+pub enum PixelFormat {
+    Unknown,
+    Rgb,
+    Bgr
 }
 
 pub struct GraphicsInfo {
     framebuffer: usize,
     width: u32,
     height: u32,
-    stride: u32
+    stride: u32,
+    format: PixelFormat
 }
 
 pub struct Regions {
@@ -51,10 +88,19 @@ pub struct Regions {
     pub length: usize
 }
 
+pub struct AcpiInfo {
+    pub rsdp: u64,
+    pub version2: bool
+}
+
 pub struct BootInfo {
     pub regions: Regions,
     pub kernel_regions: Regions,
-    pub graphics: GraphicsInfo
+    pub graphics: GraphicsInfo,
+    pub acpi: AcpiInfo,
+    pub cmdline: Cmdline,
+    pub cmdline_region: Region,
+    pub ramdisk: Region
 }
 
 impl Regions {
@@ -75,83 +121,149 @@ impl BootInfo {
         BootInfo {
             regions: Regions::empty(),
             kernel_regions: Regions::empty(),
-            graphics: GraphicsInfo::new()
+            graphics: GraphicsInfo::new(),
+            acpi: AcpiInfo::new(),
+            cmdline: Cmdline::empty(),
+            cmdline_region: Region::empty(),
+            ramdisk: Region::empty()
         }
     }
 }
 
+impl AcpiInfo {
+    fn new() -> AcpiInfo {
+        AcpiInfo { rsdp: 0, version2: false }
+    }
+}
+
 impl GraphicsInfo {
     fn new() -> GraphicsInfo {
-        GraphicsInfo { framebuffer: 0, width: 0, height: 0, stride: 0 }
+        GraphicsInfo { framebuffer: 0, width: 0, height: 0, stride: 0, format: PixelFormat::Unknown }
     }
 }
 
 struct KernelLoader {
     base: u64,
     system_table: SystemTable<Boot>,
-    regions: Vec<Region>
+    regions: Vec<Region>,
+    // Virtual address and memory size of each loadable header, recorded during `allocate`
+    // so that `load` can zero out the BSS tail that has no file-backed bytes.
+    headers: Vec<(VAddr, u64)>
 }
 
 impl ElfLoader for KernelLoader {
     fn allocate(&mut self, load_headers: LoadableHeaders) -> Result<(), ElfLoaderErr> {
         let stdout = self.system_table.stdout();
 
+        let mut start = u64::MAX;
+        let mut end = 0u64;
+
         for header in load_headers {
+            let header_start = header.virtual_addr();
+            let header_end = header_start + header.mem_size();
+
             writeln!(
                 stdout,
                 "Program header: address = {:#x}, size = {:#x}, flags = {}",
-                header.virtual_addr(),
+                header_start,
                 header.mem_size(),
                 header.flags()
             ).unwrap();
-        }
-
-        Ok(())
-    }
 
-    fn relocate(&mut self, entry: RelocationEntry) -> Result<(), ElfLoaderErr> {
-        use RelocationType::x86_64;
-        use crate::arch::x86_64::RelocationTypes::*;
+            self.headers.push((header_start, header.mem_size()));
+            start = start.min(header_start);
+            end = end.max(header_end);
+        }
 
-        let stdout = self.system_table.stdout();
-        let address: *mut u64 = (self.base + entry.offset) as *mut u64;
+        if start >= end {
+            // No loadable headers, nothing to reserve
+            return Ok(());
+        }
 
-        match entry.rtype {
-            x86_64(R_AMD64_RELATIVE) => {
-                let addend = entry.addend
-                    .ok_or(ElfLoaderErr::UnsupportedRelocationEntry)?;
-                let value = self.base + addend;
+        let aligned_start = page_align_down(start);
+        let aligned_end = page_align_up(end);
+        let pages = ((aligned_end - aligned_start) / PAGE_SIZE) as usize;
+
+        let boot_services = self.system_table.boot_services();
+        let physical_start = match boot_services.allocate_pages(
+            AllocateType::Address(aligned_start),
+            MemoryType::LOADER_DATA,
+            pages
+        ) {
+            Ok(address) => address,
+            Err(_) => {
+                let address = boot_services
+                    .allocate_pages(AllocateType::AnyPages, MemoryType::LOADER_DATA, pages)
+                    .map_err(|_| ElfLoaderErr::OutOfMemory)?;
 
                 writeln!(
                     stdout,
-                    "Relocation: AMD64_RELATIVE: *{:p} = {:#x}",
-                    address,
-                    value
+                    "Kernel load address {:#x} is unavailable, using {:#x} instead",
+                    aligned_start,
+                    address
                 ).unwrap();
 
-                unsafe {
-                    *address = value;
-                }
-
-                Ok(())
+                address
             }
-            _ => Ok(()),
+        };
+
+        // Record the slide between the linked virtual addresses and where the kernel
+        // actually ended up, so that `load`/`relocate` can translate between the two.
+        // The `AnyPages` fallback can land below `aligned_start`, making this wrap;
+        // `wrapping_sub` keeps that well-defined regardless of overflow checks.
+        self.base = physical_start.wrapping_sub(aligned_start);
+        self.regions.push(Region::new(RegionKind::Reserved, physical_start, physical_start + pages as u64 * PAGE_SIZE));
+
+        Ok(())
+    }
+
+    fn relocate(&mut self, entry: RelocationEntry) -> Result<(), ElfLoaderErr> {
+        let stdout = self.system_table.stdout();
+        let address: *mut u64 = (self.base + entry.offset) as *mut u64;
+        let value = arch::CurrentArch::relocation_value(self.base, &entry, stdout)?;
+
+        unsafe {
+            *address = value;
         }
+
+        Ok(())
     }
 
-    fn load(&mut self, _flags: Flags, base: VAddr, region: &[u8]) -> Result<(), ElfLoaderErr> {
+    fn load(&mut self, flags: Flags, base: VAddr, region: &[u8]) -> Result<(), ElfLoaderErr> {
         let stdout = self.system_table.stdout();
         let start = self.base + base;
         let end = self.base + base + region.len() as u64;
         writeln!(stdout, "Loading program header into {:#x}-{:#x}", start, end).unwrap();
 
-        // Reserve the region for the kernel
-        self.regions.push(Region::new(RegionKind::Reserved, start, end));
-
         unsafe {
             ptr::copy_nonoverlapping(region.as_ptr(), start as *mut u8, region.len());
         }
 
+        // The header's memory size can be larger than its file size (e.g. .bss), in which
+        // case the tail that has no file-backed bytes still needs to be zeroed.
+        let mem_size = self.headers.iter()
+            .find(|(virtual_addr, _)| *virtual_addr == base)
+            .map(|(_, mem_size)| *mem_size)
+            .unwrap_or(region.len() as u64);
+
+        if mem_size > region.len() as u64 {
+            let tail_start = end;
+            let tail_size = mem_size - region.len() as u64;
+
+            unsafe {
+                ptr::write_bytes(tail_start as *mut u8, 0, tail_size as usize);
+            }
+        }
+
+        // Freshly-copied executable code may still be sitting in the data cache and
+        // stale in the instruction cache on architectures that don't keep the two
+        // coherent in hardware, so the kernel would crash on its first instruction.
+        if flags.is_execute() {
+            unsafe {
+                arch::CurrentArch::sync_instruction_cache(start, end);
+            }
+        }
+
         Ok(())
     }
 
@@ -182,13 +294,34 @@ fn load_kernel_blob(boot_services: &BootServices) -> Vec<u8> {
         .expect("Failed to load kernel binary blob")
 }
 
+// The command line is optional, so a missing file is not an error
+fn load_cmdline(boot_services: &BootServices) -> Option<Vec<u8>> {
+    read_file(boot_services, "efi\\boot\\cmdline").ok()
+}
+
+// The initial ramdisk is optional, so a missing file is not an error
+fn load_initrd(boot_services: &BootServices) -> Option<Region> {
+    let blob = read_file(boot_services, "efi\\boot\\initrd").ok()?;
+
+    let pages = (page_align_up(blob.len() as u64) / PAGE_SIZE).max(1) as usize;
+    let physical_start = boot_services
+        .allocate_pages(AllocateType::AnyPages, MemoryType::LOADER_DATA, pages)
+        .expect("Failed to allocate memory for initial ramdisk");
+
+    unsafe {
+        ptr::copy_nonoverlapping(blob.as_ptr(), physical_start as *mut u8, blob.len());
+    }
+
+    Some(Region::new(RegionKind::Reserved, physical_start, physical_start + blob.len() as u64))
+}
+
 fn load_kernel(system_table: SystemTable<Boot>, info: &mut BootInfo) -> u64 {
     let boot_services = system_table.boot_services();
     let blob = load_kernel_blob(boot_services);
     let binary = ElfBinary::new(blob.as_slice())
         .expect("Failed to parse kernel binary");
 
-    let mut loader = KernelLoader { base: 0, system_table, regions: Vec::new() };
+    let mut loader = KernelLoader { base: 0, system_table, regions: Vec::new(), headers: Vec::new() };
     binary.load(&mut loader).expect("Failed to load kernel");
 
     let (data, length) = (loader.regions.as_ptr(), loader.regions.len());
@@ -198,11 +331,18 @@ fn load_kernel(system_table: SystemTable<Boot>, info: &mut BootInfo) -> u64 {
     binary.file.header.pt2.entry_point()
 }
 
-fn load_regions(system_table: &SystemTable<Boot>) -> Regions {
-    let memory_map = system_table.boot_services().memory_map(MemoryType::LOADER_DATA)
-        .expect("Failed to load memory map");
-
-    let mut regions = Vec::new();
+// Translates the final, post-exit memory map into the kernel's frame allocator view
+// of the world: only CONVENTIONAL descriptors are memory the kernel is free to reuse.
+// LOADER_*/BOOT_SERVICES_* stay Reserved even though boot services have exited,
+// because the kernel image (`allocate` above), the initrd and the command line
+// (`load_initrd`/cmdline loading) were all deliberately allocated as LOADER_DATA
+// specifically to keep them out of the available set - folding that type back into
+// Available here would hand the kernel back its own code and data as free memory.
+// `storage` must already have enough spare capacity, since nothing may allocate at
+// this point: if it ever runs out, remaining descriptors are dropped rather than
+// growing the `Vec` into a torn-down allocator.
+fn regions_from_memory_map(memory_map: &MemoryMap, storage: &mut Vec<Region>) {
+    storage.clear();
 
     for descriptor in memory_map.entries() {
         let kind = match descriptor.ty {
@@ -210,16 +350,65 @@ fn load_regions(system_table: &SystemTable<Boot>) -> Regions {
             _ => RegionKind::Reserved
         };
         let start = descriptor.phys_start;
-        let end = start + descriptor.page_count * 0x1000;
+        let end = start + descriptor.page_count * PAGE_SIZE;
+
+        // The firmware hands out descriptors one page range at a time, so adjacent
+        // ranges of the same kind are merged into a single region for the kernel.
+        if let Some(last) = storage.last_mut() {
+            let same_kind = mem::discriminant(&last.kind) == mem::discriminant(&kind);
 
-        regions.push(Region::new(kind, start, end));
+            if same_kind && last.end == start {
+                last.end = end;
+                continue;
+            }
+        }
+
+        if storage.len() == storage.capacity() {
+            break;
+        }
+
+        storage.push(Region::new(kind, start, end));
     }
+}
 
-    // Steal the region data to ourselves, so that we can pass it to the kernel
-    let (data, length) = (regions.as_ptr(), regions.len());
-    core::mem::forget(regions);
+// Looks for a `video=<width>x<height>` token in the kernel command line, e.g. `video=1920x1080`
+fn parse_requested_resolution(cmdline: &[u8]) -> Option<(u32, u32)> {
+    let text = core::str::from_utf8(cmdline).ok()?;
+
+    for token in text.split_whitespace() {
+        let Some(value) = token.strip_prefix("video=") else { continue };
+        let mut parts = value.split('x');
+        let (Some(width), Some(height)) = (parts.next(), parts.next()) else { continue };
+        let (Ok(width), Ok(height)) = (width.parse(), height.parse()) else { continue };
+
+        return Some((width, height));
+    }
+
+    None
+}
 
-    Regions::new(data, length)
+fn select_graphics_mode(gop: &GraphicsOutput, requested_resolution: Option<(u32, u32)>) -> Option<Mode> {
+    let is_usable = |mode: &Mode| matches!(
+        mode.info().pixel_format(),
+        GopPixelFormat::Rgb | GopPixelFormat::Bgr
+    );
+
+    if let Some((width, height)) = requested_resolution {
+        let exact_match = gop.modes().find(|mode| {
+            is_usable(mode) && mode.info().resolution() == (width as usize, height as usize)
+        });
+
+        if exact_match.is_some() {
+            return exact_match;
+        }
+    }
+
+    gop.modes()
+        .filter(is_usable)
+        .max_by_key(|mode| {
+            let (width, height) = mode.info().resolution();
+            width * height
+        })
 }
 
 #[entry]
@@ -243,26 +432,61 @@ unsafe fn main(
     // vector.push(2);
     // writeln!(stdout, "Vector = {:?}", vector).unwrap();
 
-    // Find RSDP for finding information of the system
-    let mut config_entries = system_table.config_table().iter();
-    let rsdp_address = config_entries
-        .find(|entry| matches!(entry.guid, cfg::ACPI_GUID | cfg::ACPI2_GUID))
-        .map(|entry| entry.address)
+    // Find RSDP for finding information of the system. ACPI 2.0 carries an XSDT (64-bit
+    // table pointers) the kernel needs for APIC/HPET enumeration, so it is preferred over
+    // a 1.0 RSDP whenever the firmware happens to publish both.
+    let config_table = system_table.config_table();
+    let rsdp_entry = config_table.iter()
+        .find(|entry| entry.guid == cfg::ACPI2_GUID)
+        .or_else(|| config_table.iter().find(|entry| entry.guid == cfg::ACPI_GUID))
         .expect("Failed to find RSDP address");
-    writeln!(stdout, "RSDP address: {:?}", rsdp_address).unwrap();
+    let rsdp_address = rsdp_entry.address as u64;
+    let acpi_version2 = rsdp_entry.guid == cfg::ACPI2_GUID;
+    writeln!(
+        stdout,
+        "RSDP address: {:#x} (ACPI {})",
+        rsdp_address,
+        if acpi_version2 { "2.0" } else { "1.0" }
+    ).unwrap();
 
-    writeln!(stdout, "Loading memory information...").unwrap();
     let mut info = BootInfo::new();
-    info.regions = load_regions(&system_table);
+    info.acpi.rsdp = rsdp_address;
+    info.acpi.version2 = acpi_version2;
 
     writeln!(stdout, "Loading kernel into memory...").unwrap();
     let entry = load_kernel(system_table.unsafe_clone(), &mut info);
 
-    // Todo: Remember to also reserve kernel load region
-
     writeln!(stdout, "Kernel is now in memory!").unwrap();
     writeln!(stdout, "Kernel entry: {:#X}", entry).unwrap();
 
+    writeln!(stdout, "Loading kernel command line...").unwrap();
+    let mut requested_resolution: Option<(u32, u32)> = None;
+    if let Some(cmdline) = load_cmdline(system_table.boot_services()) {
+        requested_resolution = parse_requested_resolution(&cmdline);
+        let (data, length) = (cmdline.as_ptr(), cmdline.len());
+
+        // The allocator hands this buffer out from whichever page(s) it happens to
+        // share with other pool allocations, so reserve the whole containing pages
+        // rather than just the exact byte range.
+        let region_start = page_align_down(data as u64);
+        let region_end = page_align_up(data as u64 + length as u64);
+        info.cmdline_region = Region::new(RegionKind::Reserved, region_start, region_end);
+
+        mem::forget(cmdline);
+        info.cmdline = Cmdline::new(data, length);
+        writeln!(stdout, "Command line is {} bytes", length).unwrap();
+    } else {
+        writeln!(stdout, "No command line file found").unwrap();
+    }
+
+    writeln!(stdout, "Loading initial ramdisk...").unwrap();
+    if let Some(ramdisk) = load_initrd(system_table.boot_services()) {
+        writeln!(stdout, "Ramdisk loaded at {:#x}-{:#x}", ramdisk.start, ramdisk.end).unwrap();
+        info.ramdisk = ramdisk;
+    } else {
+        writeln!(stdout, "No initial ramdisk file found").unwrap();
+    }
+
     // Load GOP information for displaying graphics in the kernel
     let gop_handle = system_table
         .boot_services()
@@ -274,10 +498,21 @@ unsafe fn main(
         .open_protocol_exclusive::<GraphicsOutput>(gop_handle)
         .expect("Failed to open GOP protocol");
 
+    // Firmware usually leaves the GOP in whatever mode it booted in, which may be low
+    // resolution or an unusable pixel format, so pick a better one before reading it back
+    if let Some(mode) = select_graphics_mode(&gop, requested_resolution) {
+        gop.set_mode(&mode).expect("Failed to set graphics mode");
+    }
+
     let mode_info = gop.current_mode_info();
     let (width, height) = mode_info.resolution();
     let stride = mode_info.stride() as u32 * 4;
     let framebuffer = gop.frame_buffer().as_mut_ptr() as usize;
+    let format = match mode_info.pixel_format() {
+        GopPixelFormat::Rgb => PixelFormat::Rgb,
+        GopPixelFormat::Bgr => PixelFormat::Bgr,
+        _ => PixelFormat::Unknown
+    };
 
     writeln!(stdout, "GOP mode: {:?}", mode_info).unwrap();
     writeln!(stdout, "GOP framebuffer address: {:#X}", framebuffer) .unwrap();
@@ -285,32 +520,47 @@ unsafe fn main(
     info.graphics.width = width as u32;
     info.graphics.height = height as u32;
     info.graphics.stride = stride;
+    info.graphics.format = format;
 
     writeln!(stdout, "GOP framebuffer: width={}, height={}, stride={}", width, height, stride).unwrap();
 
     writeln!(stdout, "Main is at {:#p}", main as *const u8).unwrap();
-    writeln!(stdout, "Entering kernel via {:#p}", enter_kernel as *const u8).unwrap();
-
-    // let mmap_storage = {
-    //     let max_mmap_size =
-    //         system_table.boot_services().memory_map_size() + 8 * mem::size_of::<MemoryDescriptor>();
-    //     let ptr = system_table
-    //         .boot_services()
-    //         .allocate_pool(MemoryType::LOADER_DATA, max_mmap_size)?
-    //         .unwrap();
-    //     unsafe { slice::from_raw_parts_mut(ptr, max_mmap_size) }
-    // };
-
-    // We no longer need any boot services, we're ready to enter the kernel
-    let _ = boot::exit_boot_services(MemoryType::BOOT_SERVICES_DATA);
-
-    // let (system_table, memory_map) = system_table
-    //     .exit_boot_services(image, mmap_storage)
-    //     .unwrap()
-    //     .unwrap();
-
-    enter_kernel(entry, &info);
-    loop {}
+    writeln!(stdout, "Entering kernel at {:#X}", entry).unwrap();
+
+    // The memory map snapshotted here only sizes the region storage below; it still
+    // changes at the moment of exit, so it is never itself treated as authoritative.
+    // It's also not the same snapshot `exit_boot_services` will act on: allocations
+    // made between this point and exit - including the `Vec::with_capacity` below,
+    // which is itself a pool allocation - can split a `CONVENTIONAL` descriptor in
+    // two, growing the real final map beyond what was observed here. A fixed safety
+    // margin of extra descriptor slots covers that drift in practice, but it is a
+    // heuristic, not a proof - `regions_from_memory_map` is written to degrade by
+    // dropping leftover descriptors rather than growing `storage` past this capacity,
+    // so an under-sized margin can only shrink the reported map, never allocate here.
+    const DESCRIPTOR_COUNT_SAFETY_MARGIN: usize = 8;
+    let descriptor_count_estimate = system_table
+        .boot_services()
+        .memory_map(MemoryType::LOADER_DATA)
+        .map(|memory_map| memory_map.entries().count())
+        .unwrap_or(64)
+        + DESCRIPTOR_COUNT_SAFETY_MARGIN;
+
+    // Regions can only be merged together, never split, so - modulo the safety margin
+    // above - this is an upper bound on how many end up in the final list: reserving
+    // that many slots up front means translating the post-exit map below never needs
+    // to allocate.
+    let mut regions = Vec::with_capacity(descriptor_count_estimate);
+
+    // We no longer need any boot services, we're ready to enter the kernel. Nothing
+    // from here on may allocate: boot services, and the allocator they back, are gone.
+    let memory_map = boot::exit_boot_services(MemoryType::BOOT_SERVICES_DATA);
+    regions_from_memory_map(&memory_map, &mut regions);
+
+    let (data, length) = (regions.as_ptr(), regions.len());
+    mem::forget(regions);
+    info.regions = Regions::new(data, length);
+
+    arch::CurrentArch::enter_kernel(entry, &info);
 }
 
 #[panic_handler]